@@ -0,0 +1,48 @@
+use crate::{Loan, Repay};
+
+/// Encode the Loan instruction data, discriminator included, in the exact layout `LoanInstructionData` parses
+pub fn encode_loan_ix_data(fee: u16, expiration: i64, callback_program: &[u8; 32], amounts: &[u64]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + 2 + 8 + 32 + amounts.len() * 8);
+
+    data.push(*Loan::DISCRIMINATOR);
+    data.extend_from_slice(&fee.to_le_bytes());
+    data.extend_from_slice(&expiration.to_le_bytes());
+    data.extend_from_slice(callback_program);
+    for amount in amounts {
+        data.extend_from_slice(&amount.to_le_bytes());
+    }
+
+    data
+}
+
+/// Encode the Repay instruction data, which is only the discriminator
+pub fn encode_repay_ix_data() -> Vec<u8> {
+    vec![*Repay::DISCRIMINATOR]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoanInstructionData;
+
+    #[test]
+    fn loan_ix_data_round_trips() {
+        let amounts = [1, 500_000, u64::MAX];
+        let data = encode_loan_ix_data(30, 1_700_000_000, &[3; 32], &amounts);
+
+        let (discriminator, data) = data.split_first().unwrap();
+        assert_eq!(discriminator, Loan::DISCRIMINATOR);
+
+        let instruction_data = LoanInstructionData::try_from(data).unwrap();
+        assert_eq!(instruction_data.fee, 30);
+        assert_eq!(instruction_data.expiration, 1_700_000_000);
+        assert_eq!(instruction_data.callback_program, [3; 32]);
+        let decoded: Vec<u64> = instruction_data.amounts.iter().map(|amount| u64::from_le_bytes(*amount)).collect();
+        assert_eq!(decoded, amounts);
+    }
+
+    #[test]
+    fn repay_ix_data_is_the_discriminator() {
+        assert_eq!(encode_repay_ix_data(), [*Repay::DISCRIMINATOR]);
+    }
+}
\ No newline at end of file
@@ -1,9 +1,395 @@
+use pinocchio::{account_info::AccountInfo, instruction::Signer, program_error::ProgramError, pubkey::Pubkey, sysvars::{instructions::INSTRUCTIONS_ID, rent::Rent}, ProgramResult};
+use pinocchio_token::instructions::Transfer;
+
+#[repr(C, packed)]
+pub struct LoanHeader {
+    pub borrower: [u8; 32],
+    pub callback_program: [u8; 32],
+}
+
 #[repr(C, packed)]
 pub struct LoanData {
     pub protocol_token_account: [u8; 32],
+    pub amount: u64,
+    pub fee: u64,
     pub balance: u64,
+    pub repaid: u8,
+}
+
+// Both records are packed, so they can be read and written at any offset of the account data
+const _: () = assert!(core::mem::align_of::<LoanHeader>() == 1 && core::mem::align_of::<LoanData>() == 1);
+
+pub fn load_loan_mut(data: &mut [u8]) -> Result<(&mut LoanHeader, &mut [LoanData]), ProgramError> {
+    let (header, entries) = data.split_at_mut_checked(core::mem::size_of::<LoanHeader>()).ok_or(ProgramError::InvalidAccountData)?;
+
+    // Verify that the loan account holds a whole number of LoanData entries
+    if entries.len() % core::mem::size_of::<LoanData>() != 0 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(unsafe {
+        (
+            &mut *(header.as_mut_ptr() as *mut LoanHeader),
+            core::slice::from_raw_parts_mut(
+                entries.as_mut_ptr() as *mut LoanData,
+                entries.len() / core::mem::size_of::<LoanData>()
+            ),
+        )
+    })
+}
+
+pub fn get_token_amount(data: &[u8]) -> Result<u64, ProgramError> {
+    data.get(64..72)
+        .and_then(|amount| amount.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+pub fn get_token_owner(data: &[u8]) -> Result<&[u8; 32], ProgramError> {
+    data.get(32..64)
+        .and_then(|owner| owner.try_into().ok())
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+pub fn get_token_delegate(data: &[u8]) -> Result<Option<&[u8; 32]>, ProgramError> {
+    // The delegate is stored as a COption: a u32 tag followed by the pubkey
+    let tag = data.get(72..76).ok_or(ProgramError::InvalidAccountData)?;
+    let delegate: &[u8; 32] = data.get(76..108)
+        .and_then(|delegate| delegate.try_into().ok())
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    match tag {
+        [0, 0, 0, 0] => Ok(None),
+        [1, 0, 0, 0] => Ok(Some(delegate)),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+pub fn is_token_account_frozen(data: &[u8]) -> Result<bool, ProgramError> {
+    // The account state is stored after the delegate: 0 = uninitialized, 1 = initialized, 2 = frozen
+    match data.get(108) {
+        Some(1) => Ok(false),
+        Some(2) => Ok(true),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+/// Reject a frozen token account with its own error so it isn't confused with a malformed one
+pub fn assert_not_frozen(token_account: &AccountInfo) -> ProgramResult {
+    if is_token_account_frozen(&token_account.try_borrow_data()?)? {
+        return Err(ProgramError::Immutable);
+    }
+
+    Ok(())
+}
+
+pub fn assert_rent_exempt(account: &AccountInfo, rent: &Rent) -> ProgramResult {
+    if !rent.is_exempt(account.lamports(), account.data_len()) {
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+
+    Ok(())
+}
+
+/// Transfer tokens and verify that the destination received at least `amount` minus a `max_fee_bps` tolerance
+pub fn safe_transfer(
+    from: &AccountInfo,
+    to: &AccountInfo,
+    authority: &AccountInfo,
+    amount: u64,
+    signers: &[Signer],
+    max_fee_bps: u16,
+) -> ProgramResult {
+    let balance_before = get_token_amount(&to.try_borrow_data()?)?;
+
+    Transfer {
+        from,
+        to,
+        authority,
+        amount,
+    }.invoke_signed(signers)?;
+
+    let balance_after = get_token_amount(&to.try_borrow_data()?)?;
+
+    verify_received(balance_before, balance_after, amount, max_fee_bps)
+}
+
+/// Verify that a balance going from `balance_before` to `balance_after` received at least `amount` minus a `max_fee_bps` tolerance
+pub fn verify_received(balance_before: u64, balance_after: u64, amount: u64, max_fee_bps: u16) -> ProgramResult {
+    let max_fee = amount.checked_mul(max_fee_bps as u64)
+        .and_then(|x| x.checked_div(10_000))
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    let received = balance_after.checked_sub(balance_before).ok_or(ProgramError::InvalidAccountData)?;
+
+    if received < amount.saturating_sub(max_fee) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+pub fn assert_distinct(a: &AccountInfo, b: &AccountInfo) -> ProgramResult {
+    if a.key() == b.key() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+pub fn verify_token_program(account: &AccountInfo) -> ProgramResult {
+    if account.key() != &pinocchio_token::ID {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    Ok(())
+}
+
+pub fn verify_system_program(account: &AccountInfo) -> ProgramResult {
+    if account.key() != &pinocchio_system::ID {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    Ok(())
+}
+
+/// Verify that the protocol account is the PDA derived from the loan's fee
+pub fn verify_protocol(protocol: &AccountInfo, derived: &Pubkey) -> ProgramResult {
+    if protocol.key() != derived {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    Ok(())
 }
 
-pub fn get_token_amount(data: &[u8]) -> u64 {
-    unsafe { *(data.as_ptr().add(64) as *const u64) }
+pub fn verify_instructions_sysvar(account: &AccountInfo) -> ProgramResult {
+    if account.key() != &INSTRUCTIONS_ID {
+        return Err(ProgramError::UnsupportedSysvar);
+    }
+
+    Ok(())
+}
+
+/// Stack height of an instruction invoked directly by the transaction rather than through CPI
+pub const TRANSACTION_LEVEL_STACK_HEIGHT: u64 = 1;
+
+/// Get the stack height of the current instruction, off-chain there is no CPI so it's always the transaction level
+pub fn get_stack_height() -> u64 {
+    #[cfg(target_os = "solana")]
+    unsafe {
+        pinocchio::syscalls::sol_get_stack_height()
+    }
+
+    #[cfg(not(target_os = "solana"))]
+    TRANSACTION_LEVEL_STACK_HEIGHT
+}
+
+/// Fee owed on `amount` at `fee_bps` basis points, charging at least one token unit so dust loans aren't free
+pub fn loan_fee(amount: u64, fee_bps: u16) -> Result<u64, ProgramError> {
+    let fee = amount.checked_mul(fee_bps as u64)
+        .and_then(|x| x.checked_div(10_000))
+        .ok_or(ProgramError::InvalidInstructionData)?;
+
+    if fee == 0 && amount > 0 {
+        return Ok(1);
+    }
+
+    Ok(fee)
+}
+
+pub fn balance_with_fee(balance: u64, amount: u64, fee_bps: u16) -> Result<u64, ProgramError> {
+    // The balance the protocol's token account must be back at for Repay, using the fee chosen at Loan time
+    balance.checked_add(loan_fee(amount, fee_bps)?)
+        .ok_or(ProgramError::InvalidInstructionData)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{token_account_data, TestAccount};
+
+    #[test]
+    fn assert_rent_exempt_rejects_underfunded_account() {
+        let rent = Rent {
+            lamports_per_byte_year: pinocchio::sysvars::rent::DEFAULT_LAMPORTS_PER_BYTE_YEAR,
+            exemption_threshold: pinocchio::sysvars::rent::DEFAULT_EXEMPTION_THRESHOLD,
+            burn_percent: pinocchio::sysvars::rent::DEFAULT_BURN_PERCENT,
+        };
+        let data = [0u8; 100];
+        let minimum_balance = rent.minimum_balance(data.len());
+
+        let mut funded = TestAccount::new([1; 32], crate::ID, minimum_balance, &data);
+        assert!(assert_rent_exempt(&funded.info(), &rent).is_ok());
+
+        let mut underfunded = TestAccount::new([1; 32], crate::ID, minimum_balance - 1, &data);
+        assert_eq!(assert_rent_exempt(&underfunded.info(), &rent), Err(ProgramError::AccountNotRentExempt));
+    }
+
+    #[test]
+    fn loan_fee_floors_dust_loans_to_one() {
+        assert_eq!(loan_fee(1, 1), Ok(1));
+    }
+
+    #[test]
+    fn loan_fee_is_zero_for_zero_amount() {
+        assert_eq!(loan_fee(0, 1), Ok(0));
+    }
+
+    #[test]
+    fn loan_fee_rejects_overflow() {
+        assert_eq!(loan_fee(u64::MAX, 2), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn loan_fee_in_basis_points() {
+        assert_eq!(loan_fee(1_000_000, 30), Ok(3_000));
+    }
+
+    #[test]
+    fn balance_with_fee_uses_the_loan_fee() {
+        // A 30 bps loan must be repaid at 30 bps, not at the 500 bps the program used to charge
+        assert_eq!(balance_with_fee(1_000_000, 100_000, 30), Ok(1_000_300));
+        assert_ne!(balance_with_fee(1_000_000, 100_000, 30), balance_with_fee(1_000_000, 100_000, 500));
+    }
+
+    #[test]
+    fn balance_with_fee_rejects_overflow() {
+        assert_eq!(balance_with_fee(u64::MAX, 1, 30), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn get_token_owner_reads_owner() {
+        let data = token_account_data(&[7; 32], 0, 1);
+        assert_eq!(get_token_owner(&data), Ok(&[7; 32]));
+    }
+
+    #[test]
+    fn get_token_delegate_none() {
+        let data = token_account_data(&[7; 32], 0, 1);
+        assert_eq!(get_token_delegate(&data), Ok(None));
+    }
+
+    #[test]
+    fn get_token_delegate_some() {
+        let mut data = token_account_data(&[7; 32], 0, 1);
+        data[72..76].copy_from_slice(&1u32.to_le_bytes());
+        data[76..108].copy_from_slice(&[8; 32]);
+        assert_eq!(get_token_delegate(&data), Ok(Some(&[8; 32])));
+    }
+
+    #[test]
+    fn get_token_delegate_rejects_invalid_tag() {
+        let mut data = token_account_data(&[7; 32], 0, 1);
+        data[72..76].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(get_token_delegate(&data), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn token_getters_reject_short_buffer() {
+        let data = token_account_data(&[7; 32], 0, 1);
+        assert_eq!(get_token_owner(&data[..63]), Err(ProgramError::InvalidAccountData));
+        assert_eq!(get_token_delegate(&data[..107]), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn is_token_account_frozen_reads_state() {
+        assert_eq!(is_token_account_frozen(&token_account_data(&[7; 32], 0, 0)), Err(ProgramError::InvalidAccountData));
+        assert_eq!(is_token_account_frozen(&token_account_data(&[7; 32], 0, 1)), Ok(false));
+        assert_eq!(is_token_account_frozen(&token_account_data(&[7; 32], 0, 2)), Ok(true));
+    }
+
+    #[test]
+    fn is_token_account_frozen_rejects_short_buffer() {
+        let data = token_account_data(&[7; 32], 0, 1);
+        assert_eq!(is_token_account_frozen(&data[..108]), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn assert_not_frozen_rejects_frozen_account() {
+        let mut initialized = TestAccount::new([1; 32], pinocchio_token::ID, 1, &token_account_data(&[7; 32], 0, 1));
+        let mut frozen = TestAccount::new([2; 32], pinocchio_token::ID, 1, &token_account_data(&[7; 32], 0, 2));
+        let mut uninitialized = TestAccount::new([3; 32], pinocchio_token::ID, 1, &token_account_data(&[7; 32], 0, 0));
+
+        assert_eq!(assert_not_frozen(&initialized.info()), Ok(()));
+        assert_eq!(assert_not_frozen(&frozen.info()), Err(ProgramError::Immutable));
+        assert_eq!(assert_not_frozen(&uninitialized.info()), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn verify_protocol_rejects_wrong_protocol_account() {
+        let mut protocol = TestAccount::new([4; 32], crate::ID, 1, &[]);
+
+        assert_eq!(verify_protocol(&protocol.info(), &[4; 32]), Ok(()));
+        assert_eq!(verify_protocol(&protocol.info(), &[5; 32]), Err(ProgramError::InvalidSeeds));
+    }
+
+    #[test]
+    fn verify_received_requires_full_amount_without_tolerance() {
+        assert_eq!(verify_received(50, 150, 100, 0), Ok(()));
+        assert_eq!(verify_received(50, 149, 100, 0), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn verify_received_allows_fee_within_tolerance() {
+        assert_eq!(verify_received(0, 9_900, 10_000, 100), Ok(()));
+        assert_eq!(verify_received(0, 9_899, 10_000, 100), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn verify_received_rejects_decreased_balance() {
+        assert_eq!(verify_received(100, 99, 0, 0), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn verify_received_rejects_tolerance_overflow() {
+        assert_eq!(verify_received(0, u64::MAX, u64::MAX, 2), Err(ProgramError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn safe_transfer_rejects_uncredited_destination() {
+        // The token program CPI is a no-op off-chain, so the destination balance never moves
+        let mut from = TestAccount::new([10; 32], pinocchio_token::ID, 1, &token_account_data(&[4; 32], 100, 1)).writable();
+        let mut to = TestAccount::new([11; 32], pinocchio_token::ID, 1, &token_account_data(&[1; 32], 0, 1)).writable();
+        let mut authority = TestAccount::new([4; 32], crate::ID, 0, &[]);
+
+        assert_eq!(safe_transfer(&from.info(), &to.info(), &authority.info(), 100, &[], 0), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn load_loan_mut_round_trips() {
+        let mut data = vec![0u8; core::mem::size_of::<LoanHeader>() + 2 * core::mem::size_of::<LoanData>()];
+
+        {
+            let (header, entries) = load_loan_mut(&mut data).unwrap();
+            header.borrower = [1; 32];
+            header.callback_program = [3; 32];
+            entries[0] = LoanData { protocol_token_account: [10; 32], amount: 90, fee: 10, balance: 100, repaid: 0 };
+            entries[1] = LoanData { protocol_token_account: [11; 32], amount: 1, fee: 1, balance: u64::MAX, repaid: 1 };
+        }
+
+        let (header, entries) = load_loan_mut(&mut data).unwrap();
+        assert_eq!(header.borrower, [1; 32]);
+        assert_eq!(header.callback_program, [3; 32]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].protocol_token_account, { entries[0].balance }, entries[0].repaid), ([10; 32], 100, 0));
+        assert_eq!((entries[1].protocol_token_account, { entries[1].balance }, entries[1].repaid), ([11; 32], u64::MAX, 1));
+    }
+
+    #[test]
+    fn load_loan_mut_rejects_data_shorter_than_header() {
+        let mut data = vec![0u8; core::mem::size_of::<LoanHeader>() - 1];
+        assert!(matches!(load_loan_mut(&mut data), Err(ProgramError::InvalidAccountData)));
+    }
+
+    #[test]
+    fn load_loan_mut_rejects_partial_entry() {
+        let mut data = vec![0u8; core::mem::size_of::<LoanHeader>() + core::mem::size_of::<LoanData>() + 1];
+        assert!(matches!(load_loan_mut(&mut data), Err(ProgramError::InvalidAccountData)));
+    }
+
+    #[test]
+    fn get_token_amount_reads_amount() {
+        let data = token_account_data(&[7; 32], u64::MAX - 1, 1);
+        assert_eq!(get_token_amount(&data), Ok(u64::MAX - 1));
+        assert_eq!(get_token_amount(&data[..71]), Err(ProgramError::InvalidAccountData));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,330 @@
+use core::ops::Deref;
+use pinocchio::{
+    account_info::AccountInfo,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvars::instructions::{Instructions, IntrospectedInstruction},
+    ProgramResult,
+};
+use crate::{Loan, Repay, ID};
+
+/// Read the number of instructions in the transaction from the instructions sysvar data
+pub fn load_num_instructions(data: &[u8]) -> Result<usize, ProgramError> {
+    let num_instructions: [u8; 2] = data.get(..2)
+        .and_then(|num_instructions| num_instructions.try_into().ok())
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    Ok(u16::from_le_bytes(num_instructions) as usize)
+}
+
+/// Check that the instruction targets `program_id` and starts with `discriminator`
+pub fn verify_program_and_discriminator(instruction: &IntrospectedInstruction, program_id: &Pubkey, discriminator: &u8) -> bool {
+    instruction.get_program_id() == program_id && instruction.get_instruction_data().first() == Some(discriminator)
+}
+
+/// Get the key of the account meta at `index`, failing if the instruction doesn't have that many accounts
+pub fn account_meta_key_at<'a>(instruction: &'a IntrospectedInstruction, index: usize) -> Result<&'a Pubkey, ProgramError> {
+    instruction.get_account_meta_at(index)
+        .map(|account_meta| &account_meta.key)
+        .map_err(|_| ProgramError::InvalidInstructionData)
+}
+
+/// Find the first Repay instruction for `loan` in `start..end`, returning its index and the instruction
+pub fn find_paired_repay<'a, T: Deref<Target = [u8]>>(
+    instructions: &'a Instructions<T>,
+    start: usize,
+    end: usize,
+    loan: &Pubkey,
+) -> Result<Option<(usize, IntrospectedInstruction<'a>)>, ProgramError> {
+    for index in start..end {
+        let instruction = instructions.load_instruction_at(index)?;
+
+        if verify_program_and_discriminator(&instruction, &ID, Repay::DISCRIMINATOR) && account_meta_key_at(&instruction, 1)? == loan {
+            return Ok(Some((index, instruction)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Verify the Repay instructions that follow the current Loan instruction: every Repay for `loan` must come from
+/// `borrower`, together they must settle each of the loan's `protocol_token_accounts` exactly once, no other Loan may
+/// run before the last of them, and a non-zero `callback_program` must be invoked between the Loan and the first Repay
+pub fn verify_repay_instructions<'a>(
+    instruction_sysvar: &AccountInfo,
+    loan: &Pubkey,
+    borrower: &Pubkey,
+    protocol_token_accounts: impl Iterator<Item = &'a Pubkey> + Clone,
+    callback_program: &[u8; 32],
+) -> ProgramResult {
+    let instructions = Instructions::try_from(instruction_sysvar)?;
+    let num_instructions = load_num_instructions(&instruction_sysvar.try_borrow_data()?)?;
+    let current_index = instructions.load_current_index() as usize;
+
+    // The loan can be repaid across several Repay instructions, as long as together they cover every pair exactly once,
+    // tracked as one bit per pair
+    let num_pairs = protocol_token_accounts.clone().count();
+    if num_pairs.eq(&0) || num_pairs > u64::BITS as usize {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let mut repaid = 0u64;
+    let mut first_repay_index = None;
+    let mut last_repay_index = current_index;
+
+    let mut start = current_index + 1;
+    while let Some((repay_index, instruction)) = find_paired_repay(&instructions, start, num_instructions, loan)? {
+        if account_meta_key_at(&instruction, 0)? != borrower {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        let mut account_index = 2;
+        while let Ok(key) = account_meta_key_at(&instruction, account_index) {
+            let pair = protocol_token_accounts.clone()
+                .position(|protocol_token_account| protocol_token_account == key)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+
+            if repaid & (1 << pair) != 0 {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+
+            repaid |= 1 << pair;
+            account_index += 1;
+        }
+
+        first_repay_index.get_or_insert(repay_index);
+        last_repay_index = repay_index;
+        start = repay_index + 1;
+    }
+
+    if repaid != u64::MAX >> (u64::BITS as usize - num_pairs) {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    // Reject any other Loan until this one is fully repaid, a nested Loan against the same protocol token account would
+    // record a balance that already includes this loan's repayment and let one of the fees go unpaid
+    for index in current_index + 1..last_repay_index {
+        if verify_program_and_discriminator(&instructions.load_instruction_at(index)?, &ID, Loan::DISCRIMINATOR) {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+    }
+
+    let first_repay_index = first_repay_index.ok_or(ProgramError::InvalidInstructionData)?;
+
+    // Verify that the callback program is invoked between the Loan and the first Repay instruction
+    if callback_program != &[0u8; 32] {
+        let mut callback_found = false;
+        for index in current_index + 1..first_repay_index {
+            if instructions.load_instruction_at(index)?.get_program_id() == callback_program {
+                callback_found = true;
+                break;
+            }
+        }
+
+        if !callback_found {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{instructions_sysvar_data, TestAccount, TestInstruction};
+    use pinocchio::sysvars::instructions::INSTRUCTIONS_ID;
+
+    const BORROWER: Pubkey = [1; 32];
+    const LOAN: Pubkey = [2; 32];
+    const CALLBACK: Pubkey = [3; 32];
+    const PROTOCOL_TOKEN_ACCOUNTS: [Pubkey; 3] = [[10; 32], [11; 32], [12; 32]];
+
+    fn loan() -> TestInstruction {
+        TestInstruction { program_id: ID, accounts: vec![BORROWER, [4; 32], LOAN], data: vec![0] }
+    }
+
+    fn repay(protocol_token_accounts: &[Pubkey]) -> TestInstruction {
+        let mut accounts = vec![BORROWER, LOAN];
+        accounts.extend_from_slice(protocol_token_accounts);
+        TestInstruction { program_id: ID, accounts, data: vec![1] }
+    }
+
+    fn callback() -> TestInstruction {
+        TestInstruction { program_id: CALLBACK, accounts: vec![], data: vec![] }
+    }
+
+    fn verify_at(transaction: &[TestInstruction], current_index: u16, num_pairs: usize, callback_program: &[u8; 32]) -> ProgramResult {
+        let data = instructions_sysvar_data(transaction, current_index);
+        let mut instruction_sysvar = TestAccount::new(INSTRUCTIONS_ID, [0; 32], 1, &data);
+        verify_repay_instructions(
+            &instruction_sysvar.info(),
+            &LOAN,
+            &BORROWER,
+            PROTOCOL_TOKEN_ACCOUNTS[..num_pairs].iter(),
+            callback_program,
+        )
+    }
+
+    fn verify(transaction: &[TestInstruction], num_pairs: usize, callback_program: &[u8; 32]) -> ProgramResult {
+        verify_at(transaction, 0, num_pairs, callback_program)
+    }
+
+    #[test]
+    fn rejects_wrong_instructions_sysvar() {
+        // Well-formed instructions data under another key must not be trusted as the instructions sysvar
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1])];
+        let data = instructions_sysvar_data(&transaction, 0);
+        let mut instruction_sysvar = TestAccount::new([9; 32], [0; 32], 1, &data);
+
+        assert_eq!(
+            verify_repay_instructions(&instruction_sysvar.info(), &LOAN, &BORROWER, PROTOCOL_TOKEN_ACCOUNTS[..1].iter(), &[0; 32]),
+            Err(ProgramError::UnsupportedSysvar)
+        );
+    }
+
+    #[test]
+    fn callback_between_loan_and_repay() {
+        let transaction = [loan(), callback(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1])];
+        assert_eq!(verify(&transaction, 1, &CALLBACK), Ok(()));
+    }
+
+    #[test]
+    fn callback_missing() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1])];
+        assert_eq!(verify(&transaction, 1, &CALLBACK), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn callback_only_after_repay() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]), callback()];
+        assert_eq!(verify(&transaction, 1, &CALLBACK), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn no_callback_required() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1])];
+        assert_eq!(verify(&transaction, 1, &[0; 32]), Ok(()));
+    }
+
+    #[test]
+    fn decoy_repay_for_another_loan_is_not_counted() {
+        let decoy = TestInstruction { program_id: ID, accounts: vec![BORROWER, [9; 32], PROTOCOL_TOKEN_ACCOUNTS[0]], data: vec![1] };
+        assert_eq!(verify(&[loan(), decoy], 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn decoy_repay_to_another_program_is_not_counted() {
+        let mut decoy = repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]);
+        decoy.program_id = [9; 32];
+        assert_eq!(verify(&[loan(), decoy], 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_repay_from_another_borrower() {
+        let mut decoy = repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]);
+        decoy.accounts[0] = [9; 32];
+        assert_eq!(verify(&[loan(), decoy], 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_repay_of_a_foreign_token_account() {
+        let transaction = [loan(), repay(&[[9; 32]])];
+        assert_eq!(verify(&transaction, 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_repay_before_the_loan() {
+        let transaction = [repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]), loan()];
+        assert_eq!(verify_at(&transaction, 1, 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn two_repays_cover_three_pairs() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..2]), repay(&PROTOCOL_TOKEN_ACCOUNTS[2..])];
+        assert_eq!(verify(&transaction, 3, &[0; 32]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_pair_repaid_twice() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..2]), repay(&PROTOCOL_TOKEN_ACCOUNTS[1..2])];
+        assert_eq!(verify(&transaction, 2, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+
+        // Listing a pair twice must not make up for one that is never repaid
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..2]), repay(&PROTOCOL_TOKEN_ACCOUNTS[1..2])];
+        assert_eq!(verify(&transaction, 3, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_unrepaid_pair() {
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]), repay(&PROTOCOL_TOKEN_ACCOUNTS[1..2])];
+        assert_eq!(verify(&transaction, 3, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_nested_loan_on_the_same_protocol_account() {
+        const NESTED_LOAN: Pubkey = [5; 32];
+        let nested_loan = TestInstruction { program_id: ID, accounts: vec![BORROWER, [4; 32], NESTED_LOAN], data: vec![0] };
+        let mut nested_repay = repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]);
+        nested_repay.accounts[1] = NESTED_LOAN;
+
+        // Both loans borrow from the same protocol token account and the outer one is checked from index 0
+        let transaction = [loan(), nested_loan, nested_repay, repay(&PROTOCOL_TOKEN_ACCOUNTS[..1])];
+        assert_eq!(verify(&transaction, 1, &[0; 32]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn accepts_sequential_loans() {
+        const NEXT_LOAN: Pubkey = [5; 32];
+        let next_loan = TestInstruction { program_id: ID, accounts: vec![BORROWER, [4; 32], NEXT_LOAN], data: vec![0] };
+        let mut next_repay = repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]);
+        next_repay.accounts[1] = NEXT_LOAN;
+
+        let transaction = [loan(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]), next_loan, next_repay];
+        assert_eq!(verify(&transaction, 1, &[0; 32]), Ok(()));
+    }
+
+    #[test]
+    fn load_num_instructions_reads_header() {
+        assert_eq!(load_num_instructions(&[]), Err(ProgramError::InvalidAccountData));
+        assert_eq!(load_num_instructions(&[3]), Err(ProgramError::InvalidAccountData));
+        assert_eq!(load_num_instructions(&instructions_sysvar_data(&[loan(), callback(), repay(&[])], 0)), Ok(3));
+    }
+
+    #[test]
+    fn verify_program_and_discriminator_matches_both() {
+        let data = instructions_sysvar_data(&[loan(), callback()], 0);
+        let instructions = unsafe { Instructions::new_unchecked(&*data) };
+
+        let instruction = instructions.load_instruction_at(0).unwrap();
+        assert!(verify_program_and_discriminator(&instruction, &ID, Loan::DISCRIMINATOR));
+        assert!(!verify_program_and_discriminator(&instruction, &ID, Repay::DISCRIMINATOR));
+        assert!(!verify_program_and_discriminator(&instruction, &CALLBACK, Loan::DISCRIMINATOR));
+
+        // An instruction without data has no discriminator to match
+        let instruction = instructions.load_instruction_at(1).unwrap();
+        assert!(!verify_program_and_discriminator(&instruction, &CALLBACK, Loan::DISCRIMINATOR));
+    }
+
+    #[test]
+    fn account_meta_key_at_bounds() {
+        let data = instructions_sysvar_data(&[loan()], 0);
+        let instructions = unsafe { Instructions::new_unchecked(&*data) };
+        let instruction = instructions.load_instruction_at(0).unwrap();
+
+        assert_eq!(account_meta_key_at(&instruction, 2), Ok(&LOAN));
+        assert_eq!(account_meta_key_at(&instruction, 3), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn find_paired_repay_skips_other_instructions() {
+        let transaction = [loan(), callback(), repay(&PROTOCOL_TOKEN_ACCOUNTS[..1]), repay(&PROTOCOL_TOKEN_ACCOUNTS[1..2])];
+        let data = instructions_sysvar_data(&transaction, 0);
+        let instructions = unsafe { Instructions::new_unchecked(&*data) };
+
+        assert_eq!(find_paired_repay(&instructions, 1, 4, &LOAN).unwrap().map(|(index, _)| index), Some(2));
+        assert_eq!(find_paired_repay(&instructions, 3, 4, &LOAN).unwrap().map(|(index, _)| index), Some(3));
+        assert_eq!(find_paired_repay(&instructions, 1, 2, &LOAN).unwrap().map(|(index, _)| index), None);
+        assert_eq!(find_paired_repay(&instructions, 1, 4, &[9; 32]).unwrap().map(|(index, _)| index), None);
+    }
+}
\ No newline at end of file
@@ -1,29 +1,70 @@
 use pinocchio::instruction::{Seed, Signer};
-use pinocchio::sysvars::instructions::Instructions;
+use pinocchio::sysvars::clock::Clock;
 use pinocchio::sysvars::rent::Rent;
 use pinocchio::sysvars::Sysvar;
-use pinocchio::{account_info::AccountInfo, program_error::ProgramError, ProgramResult};
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, pubkey::find_program_address, ProgramResult};
 use pinocchio_system::instructions::CreateAccount;
-use pinocchio_token::instructions::Transfer;
 use core::mem::size_of;
-use crate::{get_token_amount, LoanData, Repay, ID};
+use crate::{
+    assert_distinct, assert_not_frozen, assert_rent_exempt, balance_with_fee, get_stack_height, get_token_amount,
+    get_token_owner, load_loan_mut, safe_transfer, verify_instructions_sysvar, verify_protocol, verify_repay_instructions,
+    verify_system_program, verify_token_program, LoanData, LoanHeader, ID, TRANSACTION_LEVEL_STACK_HEIGHT,
+};
+
+/// Maximum number of (protocol, borrower) token account pairs a single loan can borrow
+///
+/// With `large-loans` it is 28, the most that fits in a transaction: see `loan_account_locks`
+#[cfg(not(feature = "large-loans"))]
+pub const MAX_LOAN_PAIRS: usize = 10;
+#[cfg(feature = "large-loans")]
+pub const MAX_LOAN_PAIRS: usize = 28;
+
+/// Maximum number of accounts a transaction can lock
+pub const MAX_TX_ACCOUNT_LOCKS: usize = 64;
+
+/// Number of accounts a Loan with `num_pairs` pairs locks: its 6 fixed accounts, 2 token accounts per pair, this program
+/// and the callback program
+pub const fn loan_account_locks(num_pairs: usize) -> usize {
+    6 + 2 * num_pairs + 2
+}
+
+// A loan with the maximum number of pairs must fit in a transaction
+const _: () = assert!(loan_account_locks(MAX_LOAN_PAIRS) <= MAX_TX_ACCOUNT_LOCKS);
+
+/// Bounds of the per-loan fee, in basis points
+pub const MIN_FEE: u16 = 1;
+pub const MAX_FEE: u16 = 500;
 
 /// #Loan
 /// 
 /// Loan tokens from the protocol
 /// 
+/// Loan must be a top-level instruction: the Repay instructions are found through the instructions sysvar, which only
+/// lists the transaction's own instructions, so a Loan invoked through CPI is rejected
+/// 
 /// Accounts:
 /// 
 /// 1. borrower:                        [signer, mut]
 /// 2. protocol:                    
 /// 3. loan:                       
-/// 4. instruction_sysvar:
+/// 4. instruction_sysvar:             // Sysvar1nstructions1111111111111111111111111
 /// 5. token_program                    [executable]
-/// ..remaining accounts are token accounts from protocol and borrower
+/// 6. system_program                   [executable]
+/// 7. ..remaining accounts are token accounts from protocol and borrower
+///
 /// Parameters:
-/// 1. bump: u8,
-/// 2. fee: u16,                        // Fee to pay to the protocol
-/// 3..n. amount: u64,                  // Amount of token to loan
+///
+/// 1. fee: u16,                        // Fee to pay to the protocol, in bps within [MIN_FEE, MAX_FEE]
+/// 2. expiration: i64,                 // Unix timestamp after which the loan is rejected
+/// 3. callback_program: [u8; 32],      // Program that must be invoked before repaying, zeroed for none
+/// 4. amounts: [u64],                  // Amount of token to loan for each pair
+///
+/// After the discriminator, the data is 42 fixed bytes followed by 8 bytes per pair, all little-endian: fee at 0,
+/// expiration at 2, callback_program at 10 and amounts from 42. The layout has no version byte, see the README for how
+/// it changed from the original `bump, fee, amounts`
+///
+/// The protocol bump was dropped from that original layout, which breaks existing clients. Loan derives the canonical
+/// bump with `find_program_address` instead, which costs about 1,500 CU for each bump it tries
 pub struct LoanAccounts<'a> {
     pub borrower: &'a AccountInfo,
     pub protocol: &'a AccountInfo,
@@ -36,22 +77,44 @@ impl<'a> TryFrom<&'a [AccountInfo]> for LoanAccounts<'a> {
     type Error = ProgramError;
 
     fn try_from(accounts: &'a [AccountInfo]) -> Result<Self, Self::Error> {
-        let [borrower, protocol, loan, instruction_sysvar, _token_program, _system_program, token_accounts @ ..] = accounts else {
+        let [borrower, protocol, loan, instruction_sysvar, token_program, system_program, token_accounts @ ..] = accounts else {
             return Err(ProgramError::NotEnoughAccountKeys);
         };
+
+        // Verify that the borrower signs and can pay for the loan account
+        if !borrower.is_signer() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if !borrower.is_writable() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Verify the programs we CPI into
+        verify_token_program(token_program)?;
+        verify_system_program(system_program)?;
+
+        // Verify the sysvar we introspect the Repay instructions from
+        verify_instructions_sysvar(instruction_sysvar)?;
         
         // Verify that the number of token accounts is valid
         if (token_accounts.len() % 2).ne(&0) || token_accounts.len().eq(&0) {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        // Check that the loan account is empty
+        // Check that the loan account is empty and isn't the borrower paying for it
         if loan.try_borrow_data()?.len().ne(&0) {
             return Err(ProgramError::InvalidAccountData);
         }
+        assert_distinct(borrower, loan)?;
 
-        // Check duplicate token accounts
+        // Check that no pair lends from and to the same token account
         let num_pairs = token_accounts.len() / 2;
+        for pair in token_accounts.chunks_exact(2) {
+            assert_distinct(&pair[0], &pair[1])?;
+        }
+
+        // Check duplicate token accounts
         for i in 0..num_pairs - 1 {
             let key_i = token_accounts[i * 2].key();
             for key_j in token_accounts[(i + 1) * 2..].iter().step_by(2).map(|acc| acc.key()) {
@@ -72,41 +135,69 @@ impl<'a> TryFrom<&'a [AccountInfo]> for LoanAccounts<'a> {
 }
 
 pub struct LoanInstructionData<'a> {
-    pub bump: [u8; 1],
     pub fee: u16,
-    pub amounts: &'a [u64],
+    pub expiration: i64,
+    pub callback_program: [u8; 32],
+    pub amounts: &'a [[u8; 8]],
 }
 
 impl<'a> TryFrom<&'a [u8]> for LoanInstructionData<'a> {
     type Error = ProgramError;
 
     fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
-        // Get the bump
-        let (bump, data) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
-
         // Get the fee
         let (fee, data) = data.split_at_checked(size_of::<u16>()).ok_or(ProgramError::InvalidInstructionData)?;
 
-        // Verify that the data is valid
-        if data.len() % size_of::<u64>() != 0 {
+        // Verify that the fee is within the protocol bounds
+        let fee = u16::from_le_bytes(fee.try_into().map_err(|_| ProgramError::InvalidInstructionData)?);
+        if !(MIN_FEE..=MAX_FEE).contains(&fee) {
             return Err(ProgramError::InvalidInstructionData);
         }
 
-        // Get the amounts
-        let amounts: &[u64] = unsafe {
+        // Get the expiration
+        let (expiration, data) = data.split_at_checked(size_of::<i64>()).ok_or(ProgramError::InvalidInstructionData)?;
+        let expiration = i64::from_le_bytes(expiration.try_into().map_err(|_| ProgramError::InvalidInstructionData)?);
+
+        // Get the callback program
+        let (callback_program, data) = data.split_at_checked(size_of::<[u8; 32]>()).ok_or(ProgramError::InvalidInstructionData)?;
+
+        // Verify that the data is valid and doesn't hold more amounts than a loan can borrow
+        if data.len() % size_of::<u64>() != 0 || data.len() / size_of::<u64>() > MAX_LOAN_PAIRS {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        // Get the amounts, kept as raw little-endian bytes since the instruction data isn't guaranteed to be 8-byte aligned
+        let amounts: &[[u8; 8]] = unsafe {
             core::slice::from_raw_parts(
-                data.as_ptr() as *const u64,
+                data.as_ptr() as *const [u8; 8],
                 data.len() / size_of::<u64>()
             )
         };
 
-        Ok(Self { bump: [*bump], fee: u16::from_le_bytes(fee.try_into().map_err(|_| ProgramError::InvalidInstructionData)?), amounts })
+        Ok(Self {
+            fee,
+            expiration,
+            callback_program: callback_program.try_into().map_err(|_| ProgramError::InvalidInstructionData)?,
+            amounts,
+        })
+    }
+}
+
+impl LoanInstructionData<'_> {
+    /// Reject the loan if `now` is past its expiration, kept out of parsing so it doesn't depend on the Clock sysvar
+    pub fn check_expiration(&self, now: i64) -> ProgramResult {
+        if now > self.expiration {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        Ok(())
     }
 }
 
 pub struct Loan<'a> {
     pub accounts: LoanAccounts<'a>,
     pub instruction_data: LoanInstructionData<'a>,
+    pub bump: [u8; 1],
 }
 
 impl<'a> TryFrom<(&'a [u8], &'a [AccountInfo])> for Loan<'a> {
@@ -116,6 +207,13 @@ impl<'a> TryFrom<(&'a [u8], &'a [AccountInfo])> for Loan<'a> {
         let accounts = LoanAccounts::try_from(accounts)?;
         let instruction_data = LoanInstructionData::try_from(data)?;
 
+        // Verify that the protocol is the canonical PDA for this fee, its canonical bump is the one we sign with
+        let (protocol, bump) = find_program_address(&[b"protocol", &instruction_data.fee.to_le_bytes()], &ID);
+        verify_protocol(accounts.protocol, &protocol)?;
+
+        // Verify that the loan hasn't expired
+        instruction_data.check_expiration(Clock::get()?.unix_timestamp)?;
+
         // Verify that the number of amounts matches the number of token accounts
         if instruction_data.amounts.len() != accounts.token_accounts.len() / 2 {
             return Err(ProgramError::InvalidInstructionData);
@@ -124,6 +222,7 @@ impl<'a> TryFrom<(&'a [u8], &'a [AccountInfo])> for Loan<'a> {
         Ok(Self {
             accounts,
             instruction_data,
+            bump: [bump],
         })
     }
 }
@@ -132,6 +231,14 @@ impl<'a> Loan<'a> {
     pub const DISCRIMINATOR: &'a u8 = &0;
     
     pub fn process(&mut self) -> ProgramResult {
+        #[cfg(feature = "cu-logging")]
+        crate::log_compute_units("Loan: entry");
+
+        // Verify that Loan is invoked by the transaction and not through CPI
+        if get_stack_height() > TRANSACTION_LEVEL_STACK_HEIGHT {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
         // Get the fee
         let fee = self.instruction_data.fee.to_le_bytes();
 
@@ -139,13 +246,14 @@ impl<'a> Loan<'a> {
         let signer_seeds = [
             Seed::from("protocol".as_bytes()),
             Seed::from(&fee),
-            Seed::from(&self.instruction_data.bump),
+            Seed::from(&self.bump),
         ];
         let signer_seeds = [Signer::from(&signer_seeds)];
 
         // Open the LoanData account and create a mutable slice to push the Loan struct to it
-        let size = size_of::<LoanData>() * self.instruction_data.amounts.len();
-        let lamports = Rent::get()?.minimum_balance(size);
+        let size = size_of::<LoanHeader>() + size_of::<LoanData>() * self.instruction_data.amounts.len();
+        let rent = Rent::get()?;
+        let lamports = rent.minimum_balance(size);
 
         CreateAccount {
             from: self.accounts.borrower,
@@ -155,59 +263,272 @@ impl<'a> Loan<'a> {
             owner: &ID,
         }.invoke()?;
 
+        // Make sure the loan account can't be collected before it gets closed by Repay
+        assert_rent_exempt(self.accounts.loan, &rent)?;
+
         let mut loan_data = self.accounts.loan.try_borrow_mut_data()?;
-        let loan_entries = unsafe {
-            core::slice::from_raw_parts_mut(
-                loan_data.as_mut_ptr() as *mut LoanData,
-                self.instruction_data.amounts.len()
-            )
-        };
+        let (loan_header, loan_entries) = load_loan_mut(&mut loan_data)?;
+        loan_header.borrower = *self.accounts.borrower.key();
+        loan_header.callback_program = self.instruction_data.callback_program;
         
         for (i, amount) in self.instruction_data.amounts.iter().enumerate() {
+            let amount = u64::from_le_bytes(*amount);
             let protocol_token_account = &self.accounts.token_accounts[i * 2];
             let borrower_token_account = &self.accounts.token_accounts[i * 2 + 1];
 
-            // Get the balance of the borrower's token account and add the fee to it so we can save it to the loan account
-            let balance = get_token_amount(&borrower_token_account.try_borrow_data()?);
-            let balance_with_fee = balance.checked_add(
-                amount.checked_mul(self.instruction_data.fee as u64)
-                    .and_then(|x| x.checked_div(10_000))
-                    .ok_or(ProgramError::InvalidInstructionData)?
-            ).ok_or(ProgramError::InvalidInstructionData)?;
+            // Verify that neither token account is frozen, the transfer and the repayment would fail otherwise
+            assert_not_frozen(protocol_token_account)?;
+            assert_not_frozen(borrower_token_account)?;
+
+            // Verify that the protocol token account is owned by the protocol
+            if get_token_owner(&protocol_token_account.try_borrow_data()?)? != self.accounts.protocol.key() {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            // Get the balance of the protocol's token account and add the fee to it so we can save it to the loan account,
+            // this is the exact balance Repay requires the protocol's token account to be back at
+            let balance = get_token_amount(&protocol_token_account.try_borrow_data()?)?;
+            let balance_with_fee = balance_with_fee(balance, amount, self.instruction_data.fee)?;
+            let fee = balance_with_fee - balance;
 
             // Push the Loan struct to the loan account
             loan_entries[i] = LoanData {
                 protocol_token_account: *protocol_token_account.key(),
+                amount,
+                fee,
                 balance: balance_with_fee,
+                repaid: 0,
             };
 
-            // Transfer the tokens from the protocol to the borrower
-            Transfer {
-                from: protocol_token_account,
-                to: borrower_token_account,
-                authority: self.accounts.protocol,
-                amount: *amount,
-            }.invoke_signed(&signer_seeds)?;
+            // Transfer the tokens from the protocol to the borrower, making sure the borrower receives the full amount
+            safe_transfer(protocol_token_account, borrower_token_account, self.accounts.protocol, amount, &signer_seeds, 0)?;
+
+            // Log the pair, see loan_record for the fields
+            #[cfg(feature = "loan-logging")]
+            pinocchio::log::sol_log_data(&crate::loan_record(
+                self.accounts.loan.key(),
+                protocol_token_account.key(),
+                borrower_token_account.key(),
+                &amount.to_le_bytes(),
+                &fee.to_le_bytes(),
+            ));
         }
 
-        // Introspecting the Repay instruction
-        let num_instructions = unsafe { *(self.accounts.instruction_sysvar.try_borrow_data()?.as_ptr() as *const u16) };
+        // Introspecting the Repay instructions
+        verify_repay_instructions(
+            self.accounts.instruction_sysvar,
+            self.accounts.loan.key(),
+            self.accounts.borrower.key(),
+            self.accounts.token_accounts.iter().step_by(2).map(AccountInfo::key),
+            &self.instruction_data.callback_program,
+        )?;
 
-        let instruction_sysvar = unsafe { Instructions::new_unchecked(self.accounts.instruction_sysvar.try_borrow_data()?) };        
-        let instruction = instruction_sysvar.load_instruction_at(num_instructions as usize - 1)?;
+        #[cfg(feature = "cu-logging")]
+        crate::log_compute_units("Loan: exit");
 
-        if instruction.get_program_id() != &crate::ID {
-            return Err(ProgramError::InvalidInstructionData);
-        }
+        Ok(())
+    }
+}
 
-        if unsafe { *(instruction.get_instruction_data().as_ptr()) } != *Repay::DISCRIMINATOR {
-            return Err(ProgramError::InvalidInstructionData);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{token_account_data, AlignedBuffer, TestAccount};
+    use pinocchio::{pubkey::Pubkey, sysvars::instructions::INSTRUCTIONS_ID};
+
+    const BORROWER: Pubkey = [1; 32];
+    const LOAN: Pubkey = [2; 32];
+    const CALLBACK: [u8; 32] = [3; 32];
+    const PROTOCOL: Pubkey = [4; 32];
+
+    // Index of each account in the Loan accounts
+    const BORROWER_ACCOUNT: usize = 0;
+    const LOAN_ACCOUNT: usize = 2;
+    const INSTRUCTION_SYSVAR: usize = 3;
+    const TOKEN_PROGRAM: usize = 4;
+    const SYSTEM_PROGRAM: usize = 5;
+    const BORROWER_TOKEN_ACCOUNT: usize = 7;
+
+    /// Accounts for a single-pair loan that pass LoanAccounts validation
+    fn loan_accounts() -> Vec<TestAccount> {
+        vec![
+            TestAccount::new(BORROWER, [0; 32], 1_000_000, &[]).signer().writable(),
+            TestAccount::new(PROTOCOL, ID, 0, &[]),
+            TestAccount::new(LOAN, [0; 32], 0, &[]).signer().writable(),
+            TestAccount::new(INSTRUCTIONS_ID, [0; 32], 1, &[]),
+            TestAccount::new(pinocchio_token::ID, [0; 32], 1, &[]),
+            TestAccount::new(pinocchio_system::ID, [0; 32], 1, &[]),
+            TestAccount::new([10; 32], pinocchio_token::ID, 1, &token_account_data(&PROTOCOL, 100, 1)).writable(),
+            TestAccount::new([11; 32], pinocchio_token::ID, 1, &token_account_data(&BORROWER, 0, 1)).writable(),
+        ]
+    }
+
+    fn validate(accounts: &mut [TestAccount]) -> ProgramResult {
+        let accounts: Vec<AccountInfo> = accounts.iter_mut().map(TestAccount::info).collect();
+        LoanAccounts::try_from(&accounts[..]).map(|_| ())
+    }
+
+    fn instruction_data(fee: u16, expiration: i64, amounts: &[u64]) -> Vec<u8> {
+        let mut data = fee.to_le_bytes().to_vec();
+        data.extend_from_slice(&expiration.to_le_bytes());
+        data.extend_from_slice(&CALLBACK);
+        for amount in amounts {
+            data.extend_from_slice(&amount.to_le_bytes());
         }
+        data
+    }
 
-        if unsafe { instruction.get_account_meta_at_unchecked(1).key } != *self.accounts.loan.key() {
-            return Err(ProgramError::InvalidInstructionData);
+    #[test]
+    fn parses_misaligned_amounts() {
+        let data = instruction_data(30, 1_000, &[1, u64::MAX, 42]);
+
+        // Offset the data by one byte from an 8-byte aligned buffer so the amounts start misaligned
+        let mut buffer = AlignedBuffer::new(data.len() + 1);
+        buffer.as_mut_slice()[1..].copy_from_slice(&data);
+        let data = &buffer[1..];
+
+        let instruction_data = LoanInstructionData::try_from(data).unwrap();
+        assert_ne!(instruction_data.amounts.as_ptr() as usize % align_of::<u64>(), 0);
+        let amounts: Vec<u64> = instruction_data.amounts.iter().map(|amount| u64::from_le_bytes(*amount)).collect();
+        assert_eq!(amounts, [1, u64::MAX, 42]);
+    }
+
+    #[test]
+    fn rejects_partial_amount() {
+        let mut data = instruction_data(30, 1_000, &[1]);
+        data.push(0);
+        assert!(matches!(LoanInstructionData::try_from(&data[..]), Err(ProgramError::InvalidInstructionData)));
+    }
+
+    #[test]
+    fn accepts_valid_accounts() {
+        assert_eq!(validate(&mut loan_accounts()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_spoofed_token_program() {
+        let mut accounts = loan_accounts();
+        accounts[TOKEN_PROGRAM] = TestAccount::new([20; 32], [0; 32], 1, &[]);
+        assert_eq!(validate(&mut accounts), Err(ProgramError::IncorrectProgramId));
+    }
+
+    #[test]
+    fn rejects_spoofed_system_program() {
+        let mut accounts = loan_accounts();
+        accounts[SYSTEM_PROGRAM] = TestAccount::new([20; 32], [0; 32], 1, &[]);
+        assert_eq!(validate(&mut accounts), Err(ProgramError::IncorrectProgramId));
+    }
+
+    #[test]
+    fn rejects_expired_loan() {
+        let data = instruction_data(30, 1_000, &[1]);
+        let instruction_data = LoanInstructionData::try_from(&data[..]).unwrap();
+        assert_eq!(instruction_data.check_expiration(1_001), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_loan_aliasing_borrower() {
+        let mut accounts = loan_accounts();
+        accounts[LOAN_ACCOUNT] = TestAccount::new(BORROWER, [0; 32], 0, &[]).signer().writable();
+        assert_eq!(validate(&mut accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn rejects_pair_aliasing_one_token_account() {
+        let mut accounts = loan_accounts();
+        accounts[BORROWER_TOKEN_ACCOUNT] = TestAccount::new([10; 32], pinocchio_token::ID, 1, &token_account_data(&PROTOCOL, 100, 1)).writable();
+        assert_eq!(validate(&mut accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn rejects_duplicate_protocol_token_account() {
+        let mut accounts = loan_accounts();
+        accounts.push(TestAccount::new([12; 32], pinocchio_token::ID, 1, &token_account_data(&PROTOCOL, 100, 1)).writable());
+        accounts.push(TestAccount::new([13; 32], pinocchio_token::ID, 1, &token_account_data(&BORROWER, 0, 1)).writable());
+        assert_eq!(validate(&mut accounts), Ok(()));
+
+        let mut accounts = loan_accounts();
+        accounts.push(TestAccount::new([10; 32], pinocchio_token::ID, 1, &token_account_data(&PROTOCOL, 100, 1)).writable());
+        accounts.push(TestAccount::new([13; 32], pinocchio_token::ID, 1, &token_account_data(&BORROWER, 0, 1)).writable());
+        assert_eq!(validate(&mut accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn rejects_non_signer_borrower() {
+        let mut accounts = loan_accounts();
+        accounts[BORROWER_ACCOUNT] = TestAccount::new(BORROWER, [0; 32], 1_000_000, &[]).writable();
+        assert_eq!(validate(&mut accounts), Err(ProgramError::MissingRequiredSignature));
+    }
+
+    #[test]
+    fn rejects_read_only_borrower() {
+        let mut accounts = loan_accounts();
+        accounts[BORROWER_ACCOUNT] = TestAccount::new(BORROWER, [0; 32], 1_000_000, &[]).signer();
+        assert_eq!(validate(&mut accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn rejects_more_amounts_than_max_loan_pairs() {
+        let data = instruction_data(30, 1_000, &[1; MAX_LOAN_PAIRS]);
+        assert_eq!(LoanInstructionData::try_from(&data[..]).map(|data| data.amounts.len()), Ok(MAX_LOAN_PAIRS));
+
+        let data = instruction_data(30, 1_000, &[1; MAX_LOAN_PAIRS + 1]);
+        assert!(matches!(LoanInstructionData::try_from(&data[..]), Err(ProgramError::InvalidInstructionData)));
+    }
+
+    #[test]
+    fn parses_instruction_data() {
+        let data = instruction_data(30, -5, &[7, 8]);
+        let instruction_data = LoanInstructionData::try_from(&data[..]).unwrap();
+
+        assert_eq!(instruction_data.fee, 30);
+        assert_eq!(instruction_data.expiration, -5);
+        assert_eq!(instruction_data.callback_program, CALLBACK);
+        assert_eq!(instruction_data.amounts, [7u64.to_le_bytes(), 8u64.to_le_bytes()]);
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let data = instruction_data(30, 1_000, &[]);
+        for len in 0..data.len() {
+            assert!(matches!(LoanInstructionData::try_from(&data[..len]), Err(ProgramError::InvalidInstructionData)));
         }
-        
-        Ok(())
+    }
+
+    #[test]
+    fn check_expiration_boundaries() {
+        let data = instruction_data(30, 1_000, &[1]);
+        let instruction_data = LoanInstructionData::try_from(&data[..]).unwrap();
+
+        assert_eq!(instruction_data.check_expiration(999), Ok(()));
+        assert_eq!(instruction_data.check_expiration(1_000), Ok(()));
+        assert_eq!(instruction_data.check_expiration(1_001), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_spoofed_instructions_sysvar() {
+        let mut accounts = loan_accounts();
+        accounts[INSTRUCTION_SYSVAR] = TestAccount::new([20; 32], [0; 32], 1, &[]);
+        assert_eq!(validate(&mut accounts), Err(ProgramError::UnsupportedSysvar));
+    }
+
+    #[test]
+    fn fee_bounds() {
+        let parse = |fee| {
+            let data = instruction_data(fee, 1_000, &[1]);
+            LoanInstructionData::try_from(&data[..]).map(|data| data.fee)
+        };
+
+        assert_eq!(parse(MIN_FEE - 1), Err(ProgramError::InvalidInstructionData));
+        assert_eq!(parse(MIN_FEE), Ok(MIN_FEE));
+        assert_eq!(parse(MAX_FEE), Ok(MAX_FEE));
+        assert_eq!(parse(MAX_FEE + 1), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[cfg(feature = "large-loans")]
+    #[test]
+    fn large_loans_cap_is_the_account_lock_limit() {
+        assert!(loan_account_locks(MAX_LOAN_PAIRS) <= MAX_TX_ACCOUNT_LOCKS);
+        assert!(loan_account_locks(MAX_LOAN_PAIRS + 1) > MAX_TX_ACCOUNT_LOCKS);
     }
 }
\ No newline at end of file
@@ -0,0 +1,65 @@
+use pinocchio::pubkey::Pubkey;
+
+#[cfg(feature = "cu-logging")]
+#[inline(always)]
+pub fn log_compute_units(label: &str) {
+    pinocchio::log::sol_log(label);
+    pinocchio::log::sol_log_compute_units();
+}
+
+/// Record logged by Loan for each pair: "loan", loan, protocol token account, borrower token account, amount, fee
+pub fn loan_record<'a>(
+    loan: &'a Pubkey,
+    protocol_token_account: &'a Pubkey,
+    borrower_token_account: &'a Pubkey,
+    amount: &'a [u8; 8],
+    fee: &'a [u8; 8],
+) -> [&'a [u8]; 6] {
+    [b"loan", loan, protocol_token_account, borrower_token_account, amount, fee]
+}
+
+/// Record logged by Repay for each pair: "repay", loan, protocol token account, amount, fee, balance after repayment
+pub fn repay_record<'a>(
+    loan: &'a Pubkey,
+    protocol_token_account: &'a Pubkey,
+    amount: &'a [u8; 8],
+    fee: &'a [u8; 8],
+    balance: &'a [u8; 8],
+) -> [&'a [u8]; 6] {
+    [b"repay", loan, protocol_token_account, amount, fee, balance]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u64(field: &[u8]) -> u64 {
+        u64::from_le_bytes(field.try_into().unwrap())
+    }
+
+    #[test]
+    fn loan_record_layout() {
+        let (amount, fee) = (1_000u64.to_le_bytes(), 3u64.to_le_bytes());
+        let record = loan_record(&[2; 32], &[10; 32], &[20; 32], &amount, &fee);
+
+        assert_eq!(record[0], b"loan");
+        assert_eq!(record[1], [2; 32]);
+        assert_eq!(record[2], [10; 32]);
+        assert_eq!(record[3], [20; 32]);
+        assert_eq!(read_u64(record[4]), 1_000);
+        assert_eq!(read_u64(record[5]), 3);
+    }
+
+    #[test]
+    fn repay_record_layout() {
+        let (amount, fee, balance) = (1_000u64.to_le_bytes(), 3u64.to_le_bytes(), 5_003u64.to_le_bytes());
+        let record = repay_record(&[2; 32], &[10; 32], &amount, &fee, &balance);
+
+        assert_eq!(record[0], b"repay");
+        assert_eq!(record[1], [2; 32]);
+        assert_eq!(record[2], [10; 32]);
+        assert_eq!(read_u64(record[3]), 1_000);
+        assert_eq!(read_u64(record[4]), 3);
+        assert_eq!(read_u64(record[5]), 5_003);
+    }
+}
\ No newline at end of file
@@ -5,4 +5,13 @@ pub mod repay;
 pub use repay::*;
 
 pub mod helpers;
-pub use helpers::*;
\ No newline at end of file
+pub use helpers::*;
+
+pub mod introspection;
+pub use introspection::*;
+
+pub mod logging;
+pub use logging::*;
+
+#[cfg(test)]
+pub mod test_utils;
\ No newline at end of file
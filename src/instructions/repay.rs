@@ -1,5 +1,5 @@
 use pinocchio::{account_info::AccountInfo, program_error::ProgramError, ProgramResult};
-use crate::{get_token_amount, LoanData};
+use crate::{get_token_amount, load_loan_mut};
 
 /// #Repay
 /// 
@@ -8,8 +8,8 @@ use crate::{get_token_amount, LoanData};
 /// Accounts:
 /// 
 /// 1. borrower:                        [signer, mut]
-/// 2. loan:
-/// 4. ..remaining accounts are token accounts from protocol
+/// 2. loan:                           [mut]
+/// 3. ..remaining accounts are token accounts from protocol, any subset of the pairs that are still pending
 pub struct RepayAccounts<'a> {
     pub borrower: &'a AccountInfo,
     pub loan: &'a AccountInfo,
@@ -50,39 +50,193 @@ impl<'a> Repay<'a> {
     pub const DISCRIMINATOR: &'a u8 = &1;
     
     pub fn process(&mut self) -> ProgramResult {  
-        let loan_data = self.accounts.loan.try_borrow_data()?;
-        let loan_num = loan_data.len() / size_of::<LoanData>();
+        #[cfg(feature = "cu-logging")]
+        crate::log_compute_units("Repay: entry");
 
-        if loan_num.ne(&self.accounts.token_accounts.len()) {
+        // Verify that the loan account was created by Loan, since we write the repaid flags back to it
+        if !self.accounts.loan.is_owned_by(&crate::ID) {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut loan_data = self.accounts.loan.try_borrow_mut_data()?;
+        let (loan_header, loan_entries) = load_loan_mut(&mut loan_data)?;
+
+        // Verify that the borrower is the one that took out the loan
+        if loan_header.borrower != *self.accounts.borrower.key() {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        // Process each pair of token accounts (protocol, borrower) with corresponding amounts
-        for i in 0..loan_num {
-            // Validate that protocol_ata is the same as the one in the loan account
-            let protocol_token_account = &self.accounts.token_accounts[i];
+        let loan_num = loan_entries.len();
 
-            if unsafe { *(loan_data.as_ptr().add(i * size_of::<LoanData>()) as *const [u8; 32]) } != *protocol_token_account.key() {
-                return Err(ProgramError::InvalidAccountData);
-            }
+        // A Repay can settle any subset of the pairs, the loan is closed once all of them are repaid
+        if loan_num.eq(&0) || self.accounts.token_accounts.is_empty() || self.accounts.token_accounts.len().gt(&loan_num) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-            // Check if the loan is already repaid
-            let balance = get_token_amount(&protocol_token_account.try_borrow_data()?);
-            let loan_balance = unsafe { *(loan_data.as_ptr().add(i * size_of::<LoanData>() + size_of::<[u8; 32]>()) as *const u64) };
-            
-            if balance < loan_balance {
+        for protocol_token_account in self.accounts.token_accounts {
+            // Find the pending entry recorded for this protocol token account
+            let loan_entry = loan_entries
+                .iter_mut()
+                .find(|entry| entry.repaid == 0 && entry.protocol_token_account == *protocol_token_account.key())
+                .ok_or(ProgramError::InvalidAccountData)?;
+
+            // Check if the loan is repaid
+            let balance = get_token_amount(&protocol_token_account.try_borrow_data()?)?;
+
+            if balance < loan_entry.balance {
                 return Err(ProgramError::InvalidAccountData);
             }
+
+            loan_entry.repaid = 1;
+
+            // Log the pair, see repay_record for the fields
+            #[cfg(feature = "loan-logging")]
+            pinocchio::log::sol_log_data(&crate::repay_record(
+                self.accounts.loan.key(),
+                protocol_token_account.key(),
+                &loan_entry.amount.to_le_bytes(),
+                &loan_entry.fee.to_le_bytes(),
+                &balance.to_le_bytes(),
+            ));
         }
 
-        // Close the loan account and give back the lamports to the borrower
-        unsafe {
-            *self.accounts.borrower.borrow_mut_lamports_unchecked() += *self.accounts.loan.borrow_lamports_unchecked();
-            *self.accounts.loan.borrow_mut_lamports_unchecked() = 0;
+        // Close the loan account and give back the lamports to the borrower once every pair is repaid
+        if loan_entries.iter().all(|entry| entry.repaid != 0) {
+            unsafe {
+                *self.accounts.borrower.borrow_mut_lamports_unchecked() += *self.accounts.loan.borrow_lamports_unchecked();
+                *self.accounts.loan.borrow_mut_lamports_unchecked() = 0;
 
-            self.accounts.loan.close_unchecked();
+                self.accounts.loan.close_unchecked();
+            }
         }
         
+        #[cfg(feature = "cu-logging")]
+        crate::log_compute_units("Repay: exit");
+
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{loan_account_data, token_account_data, TestAccount};
+    use pinocchio::pubkey::Pubkey;
+
+    const BORROWER: Pubkey = [1; 32];
+    const PROTOCOL: Pubkey = [4; 32];
+
+    fn protocol_token_account(key: u8, amount: u64) -> TestAccount {
+        TestAccount::new([key; 32], pinocchio_token::ID, 1, &token_account_data(&PROTOCOL, amount, 1)).writable()
+    }
+
+    fn repay(borrower: &mut TestAccount, loan: &mut TestAccount, token_accounts: &mut [TestAccount]) -> ProgramResult {
+        let mut accounts = vec![borrower.info(), loan.info()];
+        accounts.extend(token_accounts.iter_mut().map(TestAccount::info));
+        Repay::try_from(&accounts[..])?.process()
+    }
+
+    #[test]
+    fn rejects_mismatched_borrower() {
+        let mut borrower = TestAccount::new([9; 32], [0; 32], 1, &[]).signer().writable();
+        let mut loan = TestAccount::new([2; 32], crate::ID, 1, &loan_account_data(&BORROWER, &[([10; 32], 100)])).writable();
+        let mut token_accounts = [protocol_token_account(10, 100)];
+
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn repays_with_matching_borrower() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = TestAccount::new([2; 32], crate::ID, 1, &loan_account_data(&BORROWER, &[([10; 32], 100)])).writable();
+        let mut token_accounts = [protocol_token_account(10, 100)];
+
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Ok(()));
+    }
+
+    fn three_pair_loan() -> TestAccount {
+        let entries = [([10; 32], 100), ([11; 32], 200), ([12; 32], 300)];
+        TestAccount::new([2; 32], crate::ID, 1_000, &loan_account_data(&BORROWER, &entries)).writable()
+    }
+
+    #[test]
+    fn two_repays_settle_three_pairs() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = three_pair_loan();
+
+        let mut first = [protocol_token_account(10, 100), protocol_token_account(12, 300)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut first), Ok(()));
+        assert_eq!(loan.lamports(), 1_000);
+
+        let mut second = [protocol_token_account(11, 200)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut second), Ok(()));
+        assert_eq!(loan.lamports(), 0);
+        assert_eq!(borrower.lamports(), 1_001);
+    }
+
+    #[test]
+    fn rejects_pair_listed_twice() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = three_pair_loan();
+
+        let mut twice = [protocol_token_account(10, 100), protocol_token_account(10, 100)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut twice), Err(ProgramError::InvalidAccountData));
+
+        // A failed instruction reverts its writes on-chain, so start the second case from a fresh loan account
+        let mut loan = three_pair_loan();
+        let mut first = [protocol_token_account(10, 100)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut first), Ok(()));
+        let mut again = [protocol_token_account(10, 100)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut again), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn keeps_loan_open_while_a_pair_is_unrepaid() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = three_pair_loan();
+
+        let mut token_accounts = [protocol_token_account(10, 100), protocol_token_account(11, 200)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Ok(()));
+        assert_eq!(loan.lamports(), 1_000);
+
+        let mut loan_data = loan.data().to_vec();
+        let (_, loan_entries) = load_loan_mut(&mut loan_data).unwrap();
+        let repaid: Vec<u8> = loan_entries.iter().map(|entry| entry.repaid).collect();
+        assert_eq!(repaid, [1, 1, 0]);
+    }
+
+    #[test]
+    fn rejects_underpaid_pair() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = three_pair_loan();
+
+        let mut token_accounts = [protocol_token_account(11, 199)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn enforces_the_stored_loan_fee() {
+        // Loan stored the balance for 100_000 borrowed from 1_000_000 at 30 bps
+        let balance = crate::balance_with_fee(1_000_000, 100_000, 30).unwrap();
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+
+        let mut loan = TestAccount::new([2; 32], crate::ID, 1, &loan_account_data(&BORROWER, &[([10; 32], balance)])).writable();
+        let mut token_accounts = [protocol_token_account(10, 1_000_299)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Err(ProgramError::InvalidAccountData));
+
+        let mut loan = TestAccount::new([2; 32], crate::ID, 1, &loan_account_data(&BORROWER, &[([10; 32], balance)])).writable();
+        let mut token_accounts = [protocol_token_account(10, 1_000_300)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Ok(()));
+    }
+
+    #[test]
+    #[cfg(feature = "cu-logging")]
+    fn repays_with_cu_logging() {
+        let mut borrower = TestAccount::new(BORROWER, [0; 32], 1, &[]).signer().writable();
+        let mut loan = three_pair_loan();
+
+        let mut token_accounts = [protocol_token_account(10, 100), protocol_token_account(11, 200), protocol_token_account(12, 300)];
+        assert_eq!(repay(&mut borrower, &mut loan, &mut token_accounts), Ok(()));
+        assert_eq!(loan.lamports(), 0);
+    }
 }
\ No newline at end of file
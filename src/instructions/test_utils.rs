@@ -0,0 +1,128 @@
+use pinocchio::{account_info::AccountInfo, pubkey::Pubkey};
+
+/// Size of the runtime account header that precedes the account data
+const ACCOUNT_HEADER_LEN: usize = 88;
+
+/// An 8-byte aligned byte buffer, matching the alignment the runtime gives account data
+pub struct AlignedBuffer {
+    raw: Vec<u64>,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> Self {
+        Self { raw: vec![0u64; len.div_ceil(8)], len }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.raw.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+impl core::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.raw.as_ptr() as *const u8, self.len) }
+    }
+}
+
+/// An account laid out the way the runtime serializes it: the `Account` header followed by the data
+pub struct TestAccount {
+    buffer: AlignedBuffer,
+}
+
+impl TestAccount {
+    pub fn new(key: Pubkey, owner: Pubkey, lamports: u64, data: &[u8]) -> Self {
+        let mut buffer = AlignedBuffer::new(ACCOUNT_HEADER_LEN + data.len());
+        let bytes = buffer.as_mut_slice();
+
+        bytes[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes[8..40].copy_from_slice(&key);
+        bytes[40..72].copy_from_slice(&owner);
+        bytes[72..80].copy_from_slice(&lamports.to_le_bytes());
+        bytes[80..88].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        bytes[ACCOUNT_HEADER_LEN..].copy_from_slice(data);
+
+        Self { buffer }
+    }
+
+    pub fn signer(mut self) -> Self {
+        self.buffer.as_mut_slice()[1] = 1;
+        self
+    }
+
+    pub fn writable(mut self) -> Self {
+        self.buffer.as_mut_slice()[2] = 1;
+        self
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[ACCOUNT_HEADER_LEN..]
+    }
+
+    pub fn lamports(&self) -> u64 {
+        u64::from_le_bytes(self.buffer[72..80].try_into().unwrap())
+    }
+
+    pub fn info(&mut self) -> AccountInfo {
+        // SAFETY: `AccountInfo` is a single pointer to the account header, which `buffer` holds
+        unsafe { core::mem::transmute::<*mut u8, AccountInfo>(self.buffer.as_mut_slice().as_mut_ptr()) }
+    }
+}
+
+/// SPL token account data with the given owner, amount and state (1 = initialized, 2 = frozen)
+pub fn token_account_data(owner: &Pubkey, amount: u64, state: u8) -> Vec<u8> {
+    let mut data = vec![0u8; 165];
+    data[32..64].copy_from_slice(owner);
+    data[64..72].copy_from_slice(&amount.to_le_bytes());
+    data[108] = state;
+    data
+}
+
+/// An instruction as recorded in the instructions sysvar, all account metas are written as read-only non-signers
+pub struct TestInstruction {
+    pub program_id: Pubkey,
+    pub accounts: Vec<Pubkey>,
+    pub data: Vec<u8>,
+}
+
+/// Serialize `instructions` the way the runtime lays out the instructions sysvar data
+pub fn instructions_sysvar_data(instructions: &[TestInstruction], current_index: u16) -> AlignedBuffer {
+    let mut bytes = (instructions.len() as u16).to_le_bytes().to_vec();
+    let mut serialized = Vec::new();
+    let header_len = 2 + instructions.len() * 2;
+
+    for instruction in instructions {
+        bytes.extend_from_slice(&((header_len + serialized.len()) as u16).to_le_bytes());
+
+        serialized.extend_from_slice(&(instruction.accounts.len() as u16).to_le_bytes());
+        for key in &instruction.accounts {
+            serialized.push(0);
+            serialized.extend_from_slice(key);
+        }
+        serialized.extend_from_slice(&instruction.program_id);
+        serialized.extend_from_slice(&(instruction.data.len() as u16).to_le_bytes());
+        serialized.extend_from_slice(&instruction.data);
+    }
+
+    bytes.extend_from_slice(&serialized);
+    bytes.extend_from_slice(&current_index.to_le_bytes());
+
+    let mut buffer = AlignedBuffer::new(bytes.len());
+    buffer.as_mut_slice().copy_from_slice(&bytes);
+    buffer
+}
+
+/// Loan account data recording `borrower` and a pending entry for each (protocol token account, balance) pair
+pub fn loan_account_data(borrower: &Pubkey, entries: &[(Pubkey, u64)]) -> Vec<u8> {
+    let mut data = borrower.to_vec();
+    data.extend_from_slice(&[0; 32]);
+    for (protocol_token_account, balance) in entries {
+        data.extend_from_slice(protocol_token_account);
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&balance.to_le_bytes());
+        data.push(0);
+    }
+    data
+}
\ No newline at end of file
@@ -1,9 +1,17 @@
-use pinocchio::{account_info::AccountInfo, entrypoint, program_error::ProgramError, pubkey::Pubkey, ProgramResult};
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult};
+#[cfg(not(feature = "client"))]
+use pinocchio::entrypoint;
+
+// Clients link this crate as a library, so only the on-chain build gets the entrypoint
+#[cfg(not(feature = "client"))]
 entrypoint!(process_instruction);
 
 pub mod instructions;
 pub use instructions::*;
 
+#[cfg(feature = "client")]
+pub mod client;
+
 // 22222222222222222222222222222222222222222222
 pub const ID: Pubkey = [
     0x0f, 0x1e, 0x6b, 0x14, 0x21, 0xc0, 0x4a, 0x07, 
@@ -12,7 +20,7 @@ pub const ID: Pubkey = [
     0x8e, 0xf8, 0xaf, 0x70, 0x47, 0xdc, 0x11, 0xf7, 
 ];
 
-fn process_instruction(
+pub fn process_instruction(
     _program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
@@ -22,4 +30,19 @@ fn process_instruction(
         Some((Repay::DISCRIMINATOR, _)) => Repay::try_from(accounts)?.process(),
         _ => Err(ProgramError::InvalidInstructionData)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_empty_instruction_data() {
+        assert_eq!(process_instruction(&ID, &[], &[]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn rejects_unknown_discriminator() {
+        assert_eq!(process_instruction(&ID, &[], &[2]), Err(ProgramError::InvalidInstructionData));
+    }
 }
\ No newline at end of file